@@ -0,0 +1,19 @@
+//! The concrete `opaque_ke::CipherSuite` this server speaks
+//!
+//! Kept in its own tiny module so `opaque.rs` and `server.rs` can both refer to it without
+//! either one owning the choice.
+
+use opaque_ke::{key_exchange::tripledh::TripleDh, ksf::Identity, CipherSuite, Ristretto255};
+
+pub struct DefaultCipherSuite;
+
+impl CipherSuite for DefaultCipherSuite {
+    type OprfCs = Ristretto255;
+    type KeGroup = Ristretto255;
+    type KeyExchange = TripleDh;
+    // TODO: `Identity` applies no password-stretching before the OPRF step at all -- `opaque_ke`'s
+    // own docs call it a placeholder, not safe to ship, since it's the only thing standing between
+    // an attacker and an offline dictionary attack if the server's OPRF key ever leaks. Swap this
+    // for a real KSF (e.g. an Argon2-backed one) before this goes anywhere near production.
+    type Ksf = Identity;
+}
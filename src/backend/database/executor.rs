@@ -0,0 +1,269 @@
+//! The database actor and the messages it handles
+//!
+//! Queries are executed on a `SyncArbiter` so that the websocket actors never block on I/O
+//! themselves; they just send a message here and await the result.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use actix::prelude::*;
+use chrono::Utc;
+use failure::{format_err, Error};
+use protocol::Session;
+
+use backend::token::Token;
+
+/// Everything `DbExecutor` persists, kept behind a single lock
+///
+/// A real deployment would swap this for a connection pool; this stands in for one until then.
+#[derive(Default)]
+pub struct Storage {
+    sessions: HashMap<String, StoredSession>,
+    password_files: HashMap<String, Vec<u8>>,
+    webauthn_credentials: HashMap<String, Vec<WebAuthnCredential>>,
+}
+
+/// A session row, keyed by its refresh token
+struct StoredSession {
+    session: Session,
+    /// Set by `DeleteSession`; checked (alongside expiry) by `UpdateSession`
+    invalidated: bool,
+}
+
+/// Whether a session row should survive a `PurgeExpiredSessions` sweep
+fn is_live(stored: &StoredSession) -> bool {
+    let now = Utc::now().timestamp();
+    !stored.invalidated && Token::trusted_expires_at(&stored.session.refresh_token).map_or(false, |expires_at| expires_at > now)
+}
+
+/// Runs database queries on a dedicated sync arbiter
+///
+/// All workers spawned into the same `SyncArbiter` must be constructed from the same `Storage`
+/// handle (clone the `Arc` into each `DbExecutor::new` call) so they see a single, shared store.
+pub struct DbExecutor(Arc<Mutex<Storage>>);
+
+impl DbExecutor {
+    pub fn new(storage: Arc<Mutex<Storage>>) -> Self {
+        DbExecutor(storage)
+    }
+}
+
+impl Actor for DbExecutor {
+    type Context = SyncContext<Self>;
+}
+
+/// Persist a freshly issued access/refresh token pair
+pub struct CreateSession(pub Session);
+
+impl Message for CreateSession {
+    type Result = Result<Session, Error>;
+}
+
+impl Handler<CreateSession> for DbExecutor {
+    type Result = Result<Session, Error>;
+
+    fn handle(&mut self, msg: CreateSession, _: &mut Self::Context) -> Self::Result {
+        self.0.lock().unwrap().sessions.insert(
+            msg.0.refresh_token.clone(),
+            StoredSession {
+                session: msg.0.clone(),
+                invalidated: false,
+            },
+        );
+        Ok(msg.0)
+    }
+}
+
+/// Swap the access token of the session identified by `refresh_token` for a renewed one
+pub struct UpdateSession {
+    pub refresh_token: String,
+    pub new_access_token: String,
+}
+
+impl Message for UpdateSession {
+    type Result = Result<Session, Error>;
+}
+
+impl Handler<UpdateSession> for DbExecutor {
+    type Result = Result<Session, Error>;
+
+    fn handle(&mut self, msg: UpdateSession, _: &mut Self::Context) -> Self::Result {
+        let mut storage = self.0.lock().unwrap();
+        let stored = storage
+            .sessions
+            .get_mut(&msg.refresh_token)
+            .filter(|stored| is_live(stored))
+            .ok_or_else(|| format_err!("Unknown or invalidated session"))?;
+
+        stored.session.access_token = msg.new_access_token;
+        Ok(stored.session.clone())
+    }
+}
+
+/// Check whether the session identified by `refresh_token` is still live (exists, and hasn't been
+/// explicitly invalidated or expired), without renewing anything
+///
+/// Used on the fast path of a `Login::Session` renewal: a still-valid access token proves nothing
+/// by itself once a session can be explicitly logged out, so that path still needs this round trip.
+pub struct IsSessionLive(pub String);
+
+impl Message for IsSessionLive {
+    type Result = Result<bool, Error>;
+}
+
+impl Handler<IsSessionLive> for DbExecutor {
+    type Result = Result<bool, Error>;
+
+    fn handle(&mut self, msg: IsSessionLive, _: &mut Self::Context) -> Self::Result {
+        let storage = self.0.lock().unwrap();
+        Ok(storage.sessions.get(&msg.0).map_or(false, is_live))
+    }
+}
+
+/// Mark a session as invalidated, so it counts as expired from then on whatever its tokens say
+///
+/// This doesn't remove the row outright; the next `PurgeExpiredSessions` sweep does that, so an
+/// in-flight request that already read the old row still fails its expiry check instead of
+/// racing a hard delete.
+pub struct DeleteSession(pub String);
+
+impl Message for DeleteSession {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<DeleteSession> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeleteSession, _: &mut Self::Context) -> Self::Result {
+        if let Some(stored) = self.0.lock().unwrap().sessions.get_mut(&msg.0) {
+            stored.invalidated = true;
+        }
+        Ok(())
+    }
+}
+
+/// Delete every session that has been invalidated or whose refresh token has expired
+///
+/// Returns how many rows were purged, so the caller can log it.
+pub struct PurgeExpiredSessions;
+
+impl Message for PurgeExpiredSessions {
+    type Result = Result<usize, Error>;
+}
+
+impl Handler<PurgeExpiredSessions> for DbExecutor {
+    type Result = Result<usize, Error>;
+
+    fn handle(&mut self, _msg: PurgeExpiredSessions, _: &mut Self::Context) -> Self::Result {
+        let mut storage = self.0.lock().unwrap();
+        let before = storage.sessions.len();
+        storage.sessions.retain(|_, stored| is_live(stored));
+        Ok(before - storage.sessions.len())
+    }
+}
+
+/// Persist a user's OPAQUE password file (the output of registration)
+pub struct StorePasswordFile {
+    pub username: String,
+    pub password_file: Vec<u8>,
+}
+
+impl Message for StorePasswordFile {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<StorePasswordFile> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: StorePasswordFile, _: &mut Self::Context) -> Self::Result {
+        self.0.lock().unwrap().password_files.insert(msg.username, msg.password_file);
+        Ok(())
+    }
+}
+
+/// Look up a user's OPAQUE password file, if they've registered one
+pub struct FetchPasswordFile(pub String);
+
+impl Message for FetchPasswordFile {
+    type Result = Result<Option<Vec<u8>>, Error>;
+}
+
+impl Handler<FetchPasswordFile> for DbExecutor {
+    type Result = Result<Option<Vec<u8>>, Error>;
+
+    fn handle(&mut self, msg: FetchPasswordFile, _: &mut Self::Context) -> Self::Result {
+        Ok(self.0.lock().unwrap().password_files.get(&msg.0).cloned())
+    }
+}
+
+/// A stored WebAuthn credential, as returned by `FetchWebAuthnCredentials`
+#[derive(Debug, Clone)]
+pub struct WebAuthnCredential {
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub signature_count: u32,
+}
+
+/// Persist a newly registered WebAuthn credential for a user
+pub struct StoreWebAuthnCredential {
+    pub username: String,
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub signature_count: u32,
+}
+
+impl Message for StoreWebAuthnCredential {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<StoreWebAuthnCredential> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: StoreWebAuthnCredential, _: &mut Self::Context) -> Self::Result {
+        self.0.lock().unwrap().webauthn_credentials.entry(msg.username).or_default().push(WebAuthnCredential {
+            credential_id: msg.credential_id,
+            public_key: msg.public_key,
+            signature_count: msg.signature_count,
+        });
+        Ok(())
+    }
+}
+
+/// Look up every credential registered for a user, so they can be offered or checked against
+pub struct FetchWebAuthnCredentials(pub String);
+
+impl Message for FetchWebAuthnCredentials {
+    type Result = Result<Vec<WebAuthnCredential>, Error>;
+}
+
+impl Handler<FetchWebAuthnCredentials> for DbExecutor {
+    type Result = Result<Vec<WebAuthnCredential>, Error>;
+
+    fn handle(&mut self, msg: FetchWebAuthnCredentials, _: &mut Self::Context) -> Self::Result {
+        Ok(self.0.lock().unwrap().webauthn_credentials.get(&msg.0).cloned().unwrap_or_default())
+    }
+}
+
+/// Bump the stored signature counter after a successful assertion, to detect cloned authenticators
+pub struct UpdateSignatureCounter {
+    pub credential_id: Vec<u8>,
+    pub signature_count: u32,
+}
+
+impl Message for UpdateSignatureCounter {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<UpdateSignatureCounter> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: UpdateSignatureCounter, _: &mut Self::Context) -> Self::Result {
+        let mut storage = self.0.lock().unwrap();
+        for credential in storage.webauthn_credentials.values_mut().flatten() {
+            if credential.credential_id == msg.credential_id {
+                credential.signature_count = msg.signature_count;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+//! Database access
+
+pub mod executor;
+
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use futures::Future;
+use tokio_timer::Interval;
+
+use self::executor::{DbExecutor, PurgeExpiredSessions};
+
+/// Periodically ask `database` to purge expired sessions
+///
+/// Must be called once, after the actix system is running.
+pub fn spawn_session_reaper(database: Addr<DbExecutor>, interval: Duration) {
+    Arbiter::spawn(
+        Interval::new(Instant::now() + interval, interval)
+            .map_err(|e| error!("Session reaper interval failed: {}", e))
+            .for_each(move |_| {
+                database
+                    .send(PurgeExpiredSessions)
+                    .then(|result| {
+                        match result {
+                            Ok(Ok(count)) if count > 0 => info!("Reaped {} expired session(s)", count),
+                            Ok(Err(e)) => warn!("Failed to purge expired sessions: {}", e),
+                            Err(e) => warn!("Mailbox error while purging expired sessions: {}", e),
+                            _ => {}
+                        }
+                        Ok(())
+                    })
+            }),
+    );
+}
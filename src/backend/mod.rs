@@ -0,0 +1,9 @@
+//! Backend-only modules
+
+pub mod ciphersuite;
+pub mod database;
+pub mod opaque;
+pub mod server;
+pub mod token;
+pub mod webauthn;
+pub mod websocket;
@@ -0,0 +1,169 @@
+//! OPAQUE asymmetric PAKE primitives used by the password login flow
+//!
+//! The websocket actor only ever shuttles opaque byte blobs between the client and the
+//! database; all the actual `opaque_ke` types and the server's long-term keypair are
+//! confined to this module.
+
+use failure::{format_err, Error};
+use opaque_ke::{
+    CredentialFinalization, CredentialRequest, CredentialResponse, RegistrationRequest, RegistrationResponse,
+    RegistrationUpload, ServerLogin, ServerLoginStartParameters, ServerRegistration, ServerSetup,
+};
+use rand::rngs::OsRng;
+
+use super::ciphersuite::DefaultCipherSuite as Suite;
+
+/// Step 1 of registration: answer the client's blinded request with a `RegistrationResponse`
+///
+/// Returns the response to send to the client.
+pub fn register_start(server_setup: &ServerSetup<Suite>, username: &str, request: &[u8]) -> Result<Vec<u8>, Error> {
+    let request = RegistrationRequest::<Suite>::deserialize(request).map_err(|e| format_err!("{}", e))?;
+    let response = ServerRegistration::<Suite>::start(server_setup, request, username.as_bytes())
+        .map_err(|e| format_err!("{}", e))?;
+    Ok(response.message.serialize().to_vec())
+}
+
+/// Step 2 of registration: fold the client's `RegistrationUpload` into a password file to persist
+pub fn register_finish(upload: &[u8]) -> Result<Vec<u8>, Error> {
+    let upload = RegistrationUpload::<Suite>::deserialize(upload).map_err(|e| format_err!("{}", e))?;
+    let record = ServerRegistration::<Suite>::finish(upload);
+    Ok(record.serialize().to_vec())
+}
+
+/// Step 1 of login: run the login-start against the stored password file
+///
+/// `password_file` is `None` for an unregistered username; `opaque_ke` still produces a
+/// `CredentialResponse` in that case, indistinguishable from a real one to anyone without the
+/// server's long-term key, so callers must route both cases through here rather than
+/// short-circuiting on a missing password file themselves — that would reintroduce the
+/// username-enumeration leak OPAQUE is meant to close.
+///
+/// Returns `(credential_response, server_login_state)`; the caller must keep the state around
+/// for `login_finish`.
+pub fn login_start(
+    server_setup: &ServerSetup<Suite>,
+    username: &str,
+    password_file: Option<&[u8]>,
+    request: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut rng = OsRng;
+    let password_file = password_file
+        .map(|password_file| ServerRegistration::<Suite>::deserialize(password_file).map_err(|e| format_err!("{}", e)))
+        .transpose()?;
+    let request = CredentialRequest::<Suite>::deserialize(request).map_err(|e| format_err!("{}", e))?;
+
+    let login = ServerLogin::<Suite>::start(
+        &mut rng,
+        server_setup,
+        password_file,
+        request,
+        username.as_bytes(),
+        ServerLoginStartParameters::default(),
+    )
+    .map_err(|e| format_err!("{}", e))?;
+
+    Ok((login.message.serialize().to_vec(), login.state.serialize().to_vec()))
+}
+
+/// Step 2 of login: verify the client's finalization against the kept-around server state
+///
+/// Returns the shared session key both sides derived, on success.
+pub fn login_finish(server_state: &[u8], finalization: &[u8]) -> Result<Vec<u8>, Error> {
+    let state = ServerLogin::<Suite>::deserialize(server_state).map_err(|e| format_err!("{}", e))?;
+    let finalization = CredentialFinalization::<Suite>::deserialize(finalization).map_err(|e| format_err!("{}", e))?;
+
+    let result = state.finish(finalization).map_err(|e| format_err!("{}", e))?;
+    Ok(result.session_key.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opaque_ke::{ClientLogin, ClientLoginFinishParameters, ClientRegistration, ClientRegistrationFinishParameters};
+
+    fn register(server_setup: &ServerSetup<Suite>, username: &str, password: &[u8]) -> Vec<u8> {
+        let mut rng = OsRng;
+        let client_start = ClientRegistration::<Suite>::start(&mut rng, password).unwrap();
+        let response = register_start(server_setup, username, &client_start.message.serialize()).unwrap();
+        let response = RegistrationResponse::<Suite>::deserialize(&response).unwrap();
+
+        let client_finish = client_start
+            .state
+            .finish(&mut rng, password, response, ClientRegistrationFinishParameters::default())
+            .unwrap();
+
+        register_finish(&client_finish.message.serialize()).unwrap()
+    }
+
+    #[test]
+    fn register_then_login_round_trips_a_shared_session_key() {
+        let mut rng = OsRng;
+        let server_setup = ServerSetup::<Suite>::new(&mut rng);
+        let password_file = register(&server_setup, "alice", b"hunter2");
+
+        let client_start = ClientLogin::<Suite>::start(&mut rng, b"hunter2").unwrap();
+        let (response, server_login_state) =
+            login_start(&server_setup, "alice", Some(&password_file), &client_start.message.serialize()).unwrap();
+        let response = CredentialResponse::<Suite>::deserialize(&response).unwrap();
+
+        let client_finish = client_start
+            .state
+            .finish(b"hunter2", response, ClientLoginFinishParameters::default())
+            .unwrap();
+
+        let server_session_key = login_finish(&server_login_state, &client_finish.message.serialize()).unwrap();
+        assert_eq!(server_session_key, client_finish.session_key.to_vec());
+    }
+
+    #[test]
+    fn login_with_an_unregistered_username_still_produces_a_challenge() {
+        let mut rng = OsRng;
+        let server_setup = ServerSetup::<Suite>::new(&mut rng);
+
+        let client_start = ClientLogin::<Suite>::start(&mut rng, b"hunter2").unwrap();
+        let result = login_start(&server_setup, "nobody", None, &client_start.message.serialize());
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn client_login_finish_rejects_the_wrong_password() {
+        let mut rng = OsRng;
+        let server_setup = ServerSetup::<Suite>::new(&mut rng);
+        let password_file = register(&server_setup, "alice", b"hunter2");
+
+        let client_start = ClientLogin::<Suite>::start(&mut rng, b"wrong password").unwrap();
+        let (response, server_login_state) =
+            login_start(&server_setup, "alice", Some(&password_file), &client_start.message.serialize()).unwrap();
+        let response = CredentialResponse::<Suite>::deserialize(&response).unwrap();
+
+        let client_finish = client_start.state.finish(b"wrong password", response, ClientLoginFinishParameters::default());
+
+        // The client itself already detects the mismatch from the server's envelope; there's
+        // nothing left for `login_finish` to be handed
+        assert!(client_finish.is_err());
+    }
+
+    #[test]
+    fn login_finish_rejects_a_tampered_finalization() {
+        let mut rng = OsRng;
+        let server_setup = ServerSetup::<Suite>::new(&mut rng);
+        let password_file = register(&server_setup, "alice", b"hunter2");
+
+        let client_start = ClientLogin::<Suite>::start(&mut rng, b"hunter2").unwrap();
+        let (response, server_login_state) =
+            login_start(&server_setup, "alice", Some(&password_file), &client_start.message.serialize()).unwrap();
+        let response = CredentialResponse::<Suite>::deserialize(&response).unwrap();
+
+        let client_finish = client_start
+            .state
+            .finish(b"hunter2", response, ClientLoginFinishParameters::default())
+            .unwrap();
+
+        let mut tampered = client_finish.message.serialize().to_vec();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0xff;
+
+        assert!(login_finish(&server_login_state, &tampered).is_err());
+    }
+}
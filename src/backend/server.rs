@@ -0,0 +1,15 @@
+//! Shared actix-web application state
+
+use actix::prelude::*;
+use backend::{ciphersuite::DefaultCipherSuite, database::executor::DbExecutor, token::TokenKey};
+use opaque_ke::ServerSetup;
+use std::sync::Arc;
+
+/// State handed to every actix-web worker
+pub struct State {
+    pub database: Addr<DbExecutor>,
+    /// The server's long-term OPAQUE keypair, generated once at startup
+    pub opaque_setup: Arc<ServerSetup<DefaultCipherSuite>>,
+    /// The key session tokens are signed and verified with, generated once at startup
+    pub token_key: TokenKey,
+}
@@ -0,0 +1,162 @@
+//! Signed session tokens
+//!
+//! Every session is a pair of tokens: a short-lived *access* token that is handed back on every
+//! successful login, and a longer-lived *refresh* token that is only good for minting a new
+//! access token. Each token is `{username}.{expires_at}.{nonce}.{signature}`, HMAC-signed with a
+//! key generated once at startup, so a client can't forge a token for another user or extend its
+//! own expiry. `seconds_until_expiry` lets a caller work out how much life a token has left
+//! without a database round trip.
+
+use chrono::Utc;
+use failure::{format_err, Error};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use uuid::Uuid;
+
+/// How long a freshly minted access token stays valid
+pub const ACCESS_TOKEN_TTL_SECONDS: i64 = 15 * 60;
+/// How long a freshly minted refresh token stays valid
+pub const REFRESH_TOKEN_TTL_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+/// The key tokens are signed and verified with, generated once at startup
+///
+/// Losing this (e.g. a restart without persisting it) invalidates every outstanding token, which
+/// is the intended failure mode: there's nothing worse than a key an attacker can guess.
+#[derive(Clone)]
+pub struct TokenKey([u8; 32]);
+
+impl TokenKey {
+    pub fn generate() -> Self {
+        let mut key_bytes = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut key_bytes)
+            .expect("failed to generate a random token signing key");
+        TokenKey(key_bytes)
+    }
+
+    fn hmac_key(&self) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, &self.0)
+    }
+}
+
+/// A signed, opaque token with an expiry baked in
+pub struct Token;
+
+impl Token {
+    fn create_with_ttl(key: &TokenKey, username: &str, ttl_seconds: i64) -> String {
+        let expires_at = Utc::now().timestamp() + ttl_seconds;
+        let payload = format!("{}.{}.{}", username, expires_at, Uuid::new_v4());
+        let signature = hmac::sign(&key.hmac_key(), payload.as_bytes());
+        format!("{}.{}", payload, base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Mint a fresh access token for `username`
+    pub fn create_access(key: &TokenKey, username: &str) -> String {
+        Self::create_with_ttl(key, username, ACCESS_TOKEN_TTL_SECONDS)
+    }
+
+    /// Mint a fresh refresh token for `username`
+    pub fn create_refresh(key: &TokenKey, username: &str) -> String {
+        Self::create_with_ttl(key, username, REFRESH_TOKEN_TTL_SECONDS)
+    }
+
+    /// Verify `token`'s signature and return its `{username}.{expires_at}.{nonce}` payload
+    fn verify<'a>(key: &TokenKey, token: &'a str) -> Result<&'a str, Error> {
+        let dot = token.rfind('.').ok_or_else(|| format_err!("Malformed token"))?;
+        let (payload, signature_b64) = (&token[..dot], &token[dot + 1..]);
+
+        let signature = base64::decode_config(signature_b64, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| format_err!("Malformed token signature"))?;
+        hmac::verify(&key.hmac_key(), payload.as_bytes(), &signature).map_err(|_| format_err!("Invalid token signature"))?;
+
+        Ok(payload)
+    }
+
+    /// Seconds remaining before `token` expires, or -1 if it is expired, malformed, or unsigned by
+    /// this server
+    pub fn seconds_until_expiry(key: &TokenKey, token: &str) -> i64 {
+        Self::verify(key, token)
+            .ok()
+            .and_then(|payload| payload.splitn(3, '.').nth(1))
+            .and_then(|expires_at| expires_at.parse::<i64>().ok())
+            .map(|expires_at| expires_at - Utc::now().timestamp())
+            .filter(|remaining| *remaining > 0)
+            .unwrap_or(-1)
+    }
+
+    /// The username a token was issued for, once its signature has been checked
+    pub fn username<'a>(key: &TokenKey, token: &'a str) -> Result<&'a str, Error> {
+        Self::verify(key, token)?
+            .splitn(2, '.')
+            .next()
+            .ok_or_else(|| format_err!("Malformed token"))
+    }
+
+    /// Verify that `refresh_token` has not expired and mint a new access token from it
+    pub fn renew_access(key: &TokenKey, refresh_token: &str) -> Result<String, Error> {
+        if Self::seconds_until_expiry(key, refresh_token) < 0 {
+            return Err(format_err!("Refresh token expired"));
+        }
+
+        Ok(Self::create_access(key, Self::username(key, refresh_token)?))
+    }
+
+    /// Read the expiry timestamp embedded in a token without re-verifying its signature
+    ///
+    /// Only safe to call on a token the server minted itself and is holding onto (e.g. a row in
+    /// `Storage`); never on a token a client handed back, since an unverified expiry can be
+    /// claimed to be anything.
+    pub(crate) fn trusted_expires_at(token: &str) -> Option<i64> {
+        token.splitn(4, '.').nth(1).and_then(|expires_at| expires_at.parse::<i64>().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_username_and_expiry() {
+        let key = TokenKey::generate();
+        let token = Token::create_access(&key, "alice");
+
+        assert_eq!(Token::username(&key, &token).unwrap(), "alice");
+        assert!(Token::seconds_until_expiry(&key, &token) > 0);
+    }
+
+    #[test]
+    fn rejects_a_token_forged_with_a_different_key() {
+        let key = TokenKey::generate();
+        let other_key = TokenKey::generate();
+        let token = Token::create_access(&key, "alice");
+
+        assert_eq!(Token::seconds_until_expiry(&other_key, &token), -1);
+        assert!(Token::username(&other_key, &token).is_err());
+    }
+
+    #[test]
+    fn rejects_a_tampered_username() {
+        let key = TokenKey::generate();
+        let token = Token::create_access(&key, "alice");
+        let forged = token.replacen("alice", "bob", 1);
+
+        assert!(Token::username(&key, &forged).is_err());
+    }
+
+    #[test]
+    fn renew_access_rejects_an_expired_refresh_token() {
+        let key = TokenKey::generate();
+        let expired_refresh = Token::create_with_ttl(&key, "alice", -1);
+
+        assert!(Token::renew_access(&key, &expired_refresh).is_err());
+    }
+
+    #[test]
+    fn renew_access_mints_a_fresh_access_token() {
+        let key = TokenKey::generate();
+        let refresh = Token::create_refresh(&key, "alice");
+
+        let access = Token::renew_access(&key, &refresh).unwrap();
+        assert_eq!(Token::username(&key, &access).unwrap(), "alice");
+    }
+}
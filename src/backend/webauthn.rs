@@ -0,0 +1,331 @@
+//! WebAuthn attestation/assertion verification
+//!
+//! Enough of the spec to matter for this server: `clientDataJSON` is parsed (not
+//! substring-matched) to check the ceremony type and the challenge we issued, `authenticatorData`
+//! is parsed to check the relying-party id hash, and the COSE EC2 public key embedded in (or
+//! already on file for) the credential is used to verify the signature over the bytes the
+//! authenticator actually signed.
+
+use failure::{format_err, Error};
+use ring::{digest, signature};
+use serde::Deserialize;
+use serde_cbor::Value;
+
+use protocol::{AssertionResponse, AttestationResponse};
+
+/// The `clientData.type` an authenticator stamps on a registration ceremony
+const CLIENT_DATA_TYPE_CREATE: &str = "webauthn.create";
+/// The `clientData.type` an authenticator stamps on an authentication ceremony
+const CLIENT_DATA_TYPE_GET: &str = "webauthn.get";
+
+/// The bit in `authData`'s flags byte marking that attested credential data follows
+const ATTESTED_CREDENTIAL_DATA_FLAG: u8 = 0x40;
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ty: String,
+    challenge: String,
+    origin: String,
+}
+
+/// `authData`, split into its fixed header and whatever attested credential data followed it
+pub struct AuthenticatorData {
+    raw: Vec<u8>,
+    rp_id_hash: Vec<u8>,
+    /// The authenticator's own signature counter, parsed from the verified bytes — never trust
+    /// the client-supplied counter on the wire, since nothing ties it to what was actually signed
+    pub sign_count: u32,
+    credential_id: Option<Vec<u8>>,
+    credential_public_key: Option<Value>,
+}
+
+/// What a verified registration attestation yields, ready to persist
+pub struct VerifiedAttestation {
+    /// The credential's COSE public key, re-serialized as an uncompressed SEC1 point
+    pub public_key: Vec<u8>,
+    /// The authenticator's initial signature counter, parsed from the verified `authData`
+    pub sign_count: u32,
+}
+
+/// Verify a registration attestation against the challenge we issued
+pub fn verify_attestation(
+    rp_id: &str,
+    origin: &str,
+    challenge: &[u8],
+    attestation: &AttestationResponse,
+) -> Result<VerifiedAttestation, Error> {
+    verify_client_data(CLIENT_DATA_TYPE_CREATE, origin, challenge, &attestation.client_data_json)?;
+
+    let (auth_data, signature_bytes) = parse_attestation_object(&attestation.attestation_object)?;
+    verify_rp_id_hash(rp_id, &auth_data.rp_id_hash)?;
+
+    let credential_id = auth_data
+        .credential_id
+        .as_ref()
+        .ok_or_else(|| format_err!("Attestation has no attested credential data"))?;
+    if credential_id != &attestation.credential_id {
+        return Err(format_err!("Attested credential id does not match the one the client reported"));
+    }
+
+    let cose_key = auth_data
+        .credential_public_key
+        .as_ref()
+        .ok_or_else(|| format_err!("Attestation has no credential public key"))?;
+    let public_key = cose_ec2_to_sec1(cose_key)?;
+
+    let signed_bytes = signed_bytes(&auth_data.raw, &attestation.client_data_json);
+    verify_signature(&public_key, &signed_bytes, &signature_bytes)?;
+
+    Ok(VerifiedAttestation {
+        public_key,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+/// Verify an authentication assertion against the challenge we issued and the credential's stored
+/// public key, and return the authenticator's signature counter as it was actually signed
+pub fn verify_assertion(
+    rp_id: &str,
+    origin: &str,
+    challenge: &[u8],
+    public_key: &[u8],
+    assertion: &AssertionResponse,
+) -> Result<u32, Error> {
+    verify_client_data(CLIENT_DATA_TYPE_GET, origin, challenge, &assertion.client_data_json)?;
+
+    let auth_data = parse_authenticator_data(&assertion.authenticator_data)?;
+    verify_rp_id_hash(rp_id, &auth_data.rp_id_hash)?;
+
+    let signed_bytes = signed_bytes(&assertion.authenticator_data, &assertion.client_data_json);
+    verify_signature(public_key, &signed_bytes, &assertion.signature)?;
+
+    Ok(auth_data.sign_count)
+}
+
+/// Check `clientDataJSON`'s ceremony type, origin and that its challenge matches the one we issued
+fn verify_client_data(expected_type: &str, expected_origin: &str, challenge: &[u8], client_data_json: &[u8]) -> Result<(), Error> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)?;
+
+    if client_data.ty != expected_type {
+        return Err(format_err!("Unexpected clientData type {:?}", client_data.ty));
+    }
+
+    if client_data.origin != expected_origin {
+        return Err(format_err!("Unexpected clientData origin {:?}", client_data.origin));
+    }
+
+    let decoded_challenge = base64::decode_config(&client_data.challenge, base64::URL_SAFE_NO_PAD)?;
+    if decoded_challenge != challenge {
+        return Err(format_err!("clientData challenge does not match the one we issued"));
+    }
+
+    Ok(())
+}
+
+/// What an authenticator actually signs: the raw `authData` followed by the hash of `clientDataJSON`
+fn signed_bytes(auth_data: &[u8], client_data_json: &[u8]) -> Vec<u8> {
+    let client_data_hash = digest::digest(&digest::SHA256, client_data_json);
+    let mut signed = auth_data.to_vec();
+    signed.extend_from_slice(client_data_hash.as_ref());
+    signed
+}
+
+fn verify_rp_id_hash(rp_id: &str, rp_id_hash: &[u8]) -> Result<(), Error> {
+    let expected = digest::digest(&digest::SHA256, rp_id.as_bytes());
+    if rp_id_hash != expected.as_ref() {
+        return Err(format_err!("authData rpIdHash does not match this relying party"));
+    }
+    Ok(())
+}
+
+/// Pull `authData` and the attestation signature out of a CBOR `attestationObject`
+fn parse_attestation_object(bytes: &[u8]) -> Result<(AuthenticatorData, Vec<u8>), Error> {
+    let value: Value = serde_cbor::from_slice(bytes)?;
+    let map = match value {
+        Value::Map(map) => map,
+        _ => return Err(format_err!("attestationObject is not a CBOR map")),
+    };
+
+    let auth_data_bytes = match map.get(&Value::Text("authData".to_owned())) {
+        Some(Value::Bytes(bytes)) => bytes,
+        _ => return Err(format_err!("attestationObject has no authData")),
+    };
+
+    let att_stmt = match map.get(&Value::Text("attStmt".to_owned())) {
+        Some(Value::Map(map)) => map,
+        _ => return Err(format_err!("attestationObject has no attStmt")),
+    };
+
+    let signature = match att_stmt.get(&Value::Text("sig".to_owned())) {
+        Some(Value::Bytes(bytes)) => bytes.clone(),
+        _ => return Err(format_err!("attStmt has no sig")),
+    };
+
+    let auth_data = parse_authenticator_data(auth_data_bytes)?;
+
+    Ok((auth_data, signature))
+}
+
+/// Parse the fixed `rpIdHash || flags || signCount` header plus any attested credential data
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, Error> {
+    const HEADER_LEN: usize = 32 + 1 + 4;
+    const AAGUID_LEN: usize = 16;
+
+    if data.len() < HEADER_LEN {
+        return Err(format_err!("authData shorter than the fixed header"));
+    }
+
+    let rp_id_hash = data[0..32].to_vec();
+    let flags = data[32];
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let mut credential_id = None;
+    let mut credential_public_key = None;
+
+    if flags & ATTESTED_CREDENTIAL_DATA_FLAG != 0 {
+        let mut offset = HEADER_LEN + AAGUID_LEN;
+        if data.len() < offset + 2 {
+            return Err(format_err!("authData truncated before credential id length"));
+        }
+        let credential_id_len = u16::from_be_bytes([data[offset], data[offset + 1]]) as usize;
+        offset += 2;
+
+        if data.len() < offset + credential_id_len {
+            return Err(format_err!("authData truncated before credential id"));
+        }
+        credential_id = Some(data[offset..offset + credential_id_len].to_vec());
+        offset += credential_id_len;
+
+        credential_public_key = Some(serde_cbor::from_slice(&data[offset..])?);
+    }
+
+    Ok(AuthenticatorData {
+        raw: data.to_vec(),
+        rp_id_hash,
+        sign_count,
+        credential_id,
+        credential_public_key,
+    })
+}
+
+/// Re-serialize a COSE EC2 key (kty=2, crv=P-256) as an uncompressed SEC1 point (`0x04 || x || y`)
+fn cose_ec2_to_sec1(cose_key: &Value) -> Result<Vec<u8>, Error> {
+    let map = match cose_key {
+        Value::Map(map) => map,
+        _ => return Err(format_err!("COSE key is not a CBOR map")),
+    };
+
+    let x = match map.get(&Value::Integer(-2)) {
+        Some(Value::Bytes(bytes)) => bytes,
+        _ => return Err(format_err!("COSE key has no x coordinate")),
+    };
+    let y = match map.get(&Value::Integer(-3)) {
+        Some(Value::Bytes(bytes)) => bytes,
+        _ => return Err(format_err!("COSE key has no y coordinate")),
+    };
+
+    let mut sec1 = Vec::with_capacity(1 + x.len() + y.len());
+    sec1.push(0x04);
+    sec1.extend_from_slice(x);
+    sec1.extend_from_slice(y);
+    Ok(sec1)
+}
+
+fn verify_signature(public_key_sec1: &[u8], signed_bytes: &[u8], signature_der: &[u8]) -> Result<(), Error> {
+    let key = signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, public_key_sec1);
+    key.verify(signed_bytes, signature_der)
+        .map_err(|_| format_err!("WebAuthn signature verification failed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_ORIGIN: &str = "https://localhost";
+
+    #[test]
+    fn client_data_rejects_wrong_type() {
+        let challenge = b"abc";
+        let client_data = serde_json::json!({
+            "type": "webauthn.get",
+            "challenge": base64::encode_config(challenge, base64::URL_SAFE_NO_PAD),
+            "origin": TEST_ORIGIN,
+        });
+
+        let err = verify_client_data(CLIENT_DATA_TYPE_CREATE, TEST_ORIGIN, challenge, client_data.to_string().as_bytes())
+            .expect_err("a webauthn.get clientData shouldn't satisfy a webauthn.create check");
+        assert!(err.to_string().contains("type"));
+    }
+
+    #[test]
+    fn client_data_rejects_wrong_challenge() {
+        let client_data = serde_json::json!({
+            "type": CLIENT_DATA_TYPE_CREATE,
+            "challenge": base64::encode_config(b"not-the-challenge", base64::URL_SAFE_NO_PAD),
+            "origin": TEST_ORIGIN,
+        });
+
+        let err = verify_client_data(CLIENT_DATA_TYPE_CREATE, TEST_ORIGIN, b"expected-challenge", client_data.to_string().as_bytes())
+            .expect_err("a mismatched challenge must be rejected");
+        assert!(err.to_string().contains("challenge"));
+    }
+
+    #[test]
+    fn client_data_rejects_wrong_origin() {
+        let challenge = b"abc";
+        let client_data = serde_json::json!({
+            "type": CLIENT_DATA_TYPE_CREATE,
+            "challenge": base64::encode_config(challenge, base64::URL_SAFE_NO_PAD),
+            "origin": "https://evil.example",
+        });
+
+        let err = verify_client_data(CLIENT_DATA_TYPE_CREATE, TEST_ORIGIN, challenge, client_data.to_string().as_bytes())
+            .expect_err("an unexpected origin must be rejected");
+        assert!(err.to_string().contains("origin"));
+    }
+
+    #[test]
+    fn client_data_accepts_matching_challenge() {
+        let challenge = b"the-right-challenge";
+        let client_data = serde_json::json!({
+            "type": CLIENT_DATA_TYPE_GET,
+            "challenge": base64::encode_config(challenge, base64::URL_SAFE_NO_PAD),
+            "origin": TEST_ORIGIN,
+        });
+
+        verify_client_data(CLIENT_DATA_TYPE_GET, TEST_ORIGIN, challenge, client_data.to_string().as_bytes())
+            .expect("matching type, origin and challenge should verify");
+    }
+
+    #[test]
+    fn rp_id_hash_must_match() {
+        let hash = digest::digest(&digest::SHA256, b"localhost");
+        verify_rp_id_hash("localhost", hash.as_ref()).expect("hash of the right rp_id should match");
+        assert!(verify_rp_id_hash("evil.example", hash.as_ref()).is_err());
+    }
+
+    #[test]
+    fn sign_count_is_parsed_from_auth_data_not_trusted_input() {
+        let mut auth_data = vec![0u8; 37];
+        auth_data[32] = 0; // no attested credential data
+        auth_data[33..37].copy_from_slice(&42u32.to_be_bytes());
+
+        let parsed = parse_authenticator_data(&auth_data).expect("well-formed fixed header should parse");
+        assert_eq!(parsed.sign_count, 42);
+    }
+
+    #[test]
+    fn cose_ec2_round_trips_to_sec1() {
+        let mut map = std::collections::BTreeMap::new();
+        map.insert(Value::Integer(-2), Value::Bytes(vec![1; 32]));
+        map.insert(Value::Integer(-3), Value::Bytes(vec![2; 32]));
+        let cose_key = Value::Map(map.into_iter().collect());
+
+        let sec1 = cose_ec2_to_sec1(&cose_key).expect("well-formed EC2 key should convert");
+        assert_eq!(sec1.len(), 65);
+        assert_eq!(sec1[0], 0x04);
+        assert_eq!(&sec1[1..33], &[1; 32][..]);
+        assert_eq!(&sec1[33..], &[2; 32][..]);
+    }
+}
@@ -6,29 +6,131 @@ use actix_web::{
     Binary,
 };
 use backend::{
-    database::executor::{CreateSession, DeleteSession, UpdateSession},
+    database::executor::{
+        CreateSession, DbExecutor, DeleteSession, FetchPasswordFile, FetchWebAuthnCredentials, IsSessionLive,
+        StorePasswordFile, StoreWebAuthnCredential, UpdateSession, UpdateSignatureCounter,
+    },
+    opaque,
     server::State,
     token::Token,
+    webauthn,
 };
 use failure::Error;
 use futures::Future;
-use protocol::{Login, Request, Response, ResponseError, Session};
+use protocol::{
+    AssertionResponse, AttestationResponse, Login, PublicKeyCredentialCreationOptions,
+    PublicKeyCredentialRequestOptions, Request, Response, ResponseError, Session,
+};
+use rand::{thread_rng, RngCore};
 use serde_cbor::{from_slice, ser::to_vec_packed};
+use std::time::{Duration, Instant};
+
+/// The relying party id/name advertised to authenticators
+const RP_ID: &str = "localhost";
+const RP_NAME: &str = "webapp.rs";
+/// The only origin WebAuthn ceremonies are accepted from
+const RP_ORIGIN: &str = "https://localhost";
+
+/// A default interval between heartbeat pings, for passing to `WebSocket::new`
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// A default idle time before a silent connection is dropped, for passing to `WebSocket::new`
+pub const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// An in-flight response, chained from one or more database calls without blocking the actor
+type Fut<T> = Box<dyn ActorFuture<Item = T, Error = ResponseError, Actor = WebSocket>>;
+
+/// Turn the mailbox future of a database message into a plain `ResponseError` on any failure,
+/// whether the mailbox itself or the query failed
+fn db_call<T, M>(request: actix::dev::Request<DbExecutor, M>) -> impl Future<Item = T, Error = ResponseError>
+where
+    M: Message<Result = Result<T, Error>> + Send + 'static,
+    M::Result: Send,
+    T: Send + 'static,
+{
+    request
+        .map_err(|_| ResponseError::Database)
+        .and_then(|result| result.map_err(ResponseError::from))
+}
+
+/// Challenge state kept between the two messages of a WebAuthn registration ceremony
+struct PendingRegistration {
+    username: String,
+    challenge: Vec<u8>,
+}
+
+/// Challenge state kept between the two messages of a WebAuthn authentication ceremony
+struct PendingAuthentication {
+    username: String,
+    challenge: Vec<u8>,
+}
+
+/// The username kept between the two messages of an OPAQUE registration exchange
+struct PendingOpaqueRegistration {
+    username: String,
+}
+
+/// OPAQUE server state kept between the two messages of a login exchange
+struct PendingOpaqueLogin {
+    username: String,
+    server_login_state: Vec<u8>,
+}
 
 /// The actual websocket
-pub struct WebSocket;
+pub struct WebSocket {
+    /// How often we ping an idle connection
+    heartbeat_interval: Duration,
+    /// How long a connection may go without any traffic before we drop it
+    client_timeout: Duration,
+    /// When we last heard anything (`Ping`, `Pong` or `Binary`) from the client
+    last_seen: Instant,
+    /// The session this connection last logged in as, if any, so we can clean it up on disconnect
+    current_session: Option<Session>,
+    pending_webauthn_registration: Option<PendingRegistration>,
+    pending_webauthn_authentication: Option<PendingAuthentication>,
+    pending_opaque_registration: Option<PendingOpaqueRegistration>,
+    pending_opaque_login: Option<PendingOpaqueLogin>,
+}
 
 impl Actor for WebSocket {
     type Context = WebsocketContext<Self, State>;
+
+    fn started(&mut self, context: &mut Self::Context) {
+        let heartbeat_interval = self.heartbeat_interval;
+        let client_timeout = self.client_timeout;
+
+        context.run_interval(heartbeat_interval, move |actor, context| {
+            if Instant::now().duration_since(actor.last_seen) > client_timeout {
+                info!("Client has been idle for too long, disconnecting");
+                context.stop();
+                return;
+            }
+
+            context.ping("");
+        });
+    }
+
+    fn stopped(&mut self, context: &mut Self::Context) {
+        self.cleanup_session(context);
+    }
 }
 
 /// Handler for `Message`
 impl StreamHandler<Message, ProtocolError> for WebSocket {
     fn handle(&mut self, msg: Message, context: &mut Self::Context) {
         match msg {
-            Message::Binary(bin) => if let Err(e) = self.handle_request(&bin, context) {
-                warn!("Unable to send response: {}", e);
-            },
+            Message::Binary(bin) => {
+                self.last_seen = Instant::now();
+                if let Err(e) = self.handle_request(&bin, context) {
+                    warn!("Unable to handle request: {}", e);
+                }
+            }
+            Message::Ping(msg) => {
+                self.last_seen = Instant::now();
+                context.pong(&msg);
+            }
+            Message::Pong(_) => {
+                self.last_seen = Instant::now();
+            }
             Message::Close(reason) => {
                 info!("Closing websocket connection: {:?}", reason);
                 context.stop();
@@ -39,8 +141,40 @@ impl StreamHandler<Message, ProtocolError> for WebSocket {
 }
 
 impl WebSocket {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(heartbeat_interval: Duration, client_timeout: Duration) -> Self {
+        Self {
+            heartbeat_interval,
+            client_timeout,
+            last_seen: Instant::now(),
+            current_session: None,
+            pending_webauthn_registration: None,
+            pending_webauthn_authentication: None,
+            pending_opaque_registration: None,
+            pending_opaque_login: None,
+        }
+    }
+
+    /// Log out and drop the session bound to this connection, if any
+    ///
+    /// Best-effort and fire-and-forget: the actor may already be on its way out, so we don't wait
+    /// for the database to confirm before returning.
+    fn cleanup_session(&mut self, context: &mut WebsocketContext<Self, State>) {
+        if let Some(session) = self.current_session.take() {
+            debug!("Connection closed, cleaning up its session");
+            context.spawn(
+                context
+                    .state()
+                    .database
+                    .send(DeleteSession(session.refresh_token))
+                    .into_actor(self)
+                    .then(|result, _, _| {
+                        if let Err(e) = result {
+                            warn!("Failed to clean up session on disconnect: {}", e);
+                        }
+                        actix::fut::ok(())
+                    }),
+            );
+        }
     }
 
     fn handle_request(&mut self, data: &Binary, context: &mut WebsocketContext<Self, State>) -> Result<(), Error> {
@@ -52,102 +186,442 @@ impl WebSocket {
             Request::Login(login) => {
                 // Check if its a credential or token login type
                 match login {
-                    Login::Credentials {
-                        username: u,
-                        password: p,
+                    Login::Session(s) => {
+                        let fut = self.handle_request_login_token(s, context).then(|result, actor, context| {
+                            actor.respond(context, Response::Login(result));
+                            actix::fut::ok(())
+                        });
+                        context.spawn(fut);
+                        Ok(())
+                    }
+                    Login::WebAuthnRegisterStart { username } => {
+                        let fut = self
+                            .handle_request_webauthn_register_start(username, context)
+                            .then(|result, actor, context| {
+                                actor.respond(context, Response::WebAuthnRegisterChallenge(result));
+                                actix::fut::ok(())
+                            });
+                        context.spawn(fut);
+                        Ok(())
+                    }
+                    Login::WebAuthnRegisterFinish { username, attestation } => {
+                        let fut = self
+                            .handle_request_webauthn_register_finish(username, attestation, context)
+                            .then(|result, actor, context| {
+                                actor.respond(context, Response::WebAuthnRegistered(result));
+                                actix::fut::ok(())
+                            });
+                        context.spawn(fut);
+                        Ok(())
+                    }
+                    Login::WebAuthnLoginStart { username } => {
+                        let fut = self
+                            .handle_request_webauthn_login_start(username, context)
+                            .then(|result, actor, context| {
+                                actor.respond(context, Response::WebAuthnLoginChallenge(result));
+                                actix::fut::ok(())
+                            });
+                        context.spawn(fut);
+                        Ok(())
+                    }
+                    Login::WebAuthnLoginFinish { username, assertion } => {
+                        let fut = self
+                            .handle_request_webauthn_login_finish(username, assertion, context)
+                            .then(|result, actor, context| {
+                                actor.respond(context, Response::Login(result));
+                                actix::fut::ok(())
+                            });
+                        context.spawn(fut);
+                        Ok(())
+                    }
+                    Login::OpaqueRegisterStart {
+                        username,
+                        registration_request,
                     } => {
-                        let response = Response::Login(self.handle_request_login_credentials(&u, &p, context));
-
-                        // Send the response to the websocket
-                        self.send(context, &response)?;
+                        let fut = self
+                            .handle_request_opaque_register_start(username, registration_request, context)
+                            .then(|result, actor, context| {
+                                actor.respond(context, Response::OpaqueRegisterChallenge(result));
+                                actix::fut::ok(())
+                            });
+                        context.spawn(fut);
                         Ok(())
                     }
-                    Login::Session(s) => {
-                        let response = Response::Login(self.handle_request_login_token(&s, context));
-
-                        // Send the response to the websocket
-                        self.send(context, &response)?;
+                    Login::OpaqueRegisterFinish {
+                        username,
+                        registration_upload,
+                    } => {
+                        let fut = self
+                            .handle_request_opaque_register_finish(username, registration_upload, context)
+                            .then(|result, actor, context| {
+                                actor.respond(context, Response::OpaqueRegistered(result));
+                                actix::fut::ok(())
+                            });
+                        context.spawn(fut);
+                        Ok(())
+                    }
+                    Login::OpaqueLoginStart {
+                        username,
+                        credential_request,
+                    } => {
+                        let fut = self
+                            .handle_request_opaque_login_start(username, credential_request, context)
+                            .then(|result, actor, context| {
+                                actor.respond(context, Response::OpaqueLoginChallenge(result));
+                                actix::fut::ok(())
+                            });
+                        context.spawn(fut);
+                        Ok(())
+                    }
+                    Login::OpaqueLoginFinish {
+                        username,
+                        credential_finalization,
+                    } => {
+                        let fut = self
+                            .handle_request_opaque_login_finish(username, credential_finalization, context)
+                            .then(|result, actor, context| {
+                                actor.respond(context, Response::Login(result));
+                                actix::fut::ok(())
+                            });
+                        context.spawn(fut);
                         Ok(())
                     }
                 }
             }
             Request::Logout(s) => {
-                let response = Response::Logout(self.handle_request_logout(s, context));
-
-                // Send the response to the websocket
-                self.send(context, &response)?;
+                let fut = self.handle_request_logout(s, context).then(|result, actor, context| {
+                    if result.is_ok() {
+                        actor.current_session = None;
+                    }
+                    actor.respond(context, Response::Logout(result));
+                    actix::fut::ok(())
+                });
+                context.spawn(fut);
                 Ok(())
             }
         }
     }
 
+    /// Serialize a response, remember the session it carries (if any) and send it to the websocket
+    fn respond(&mut self, context: &mut WebsocketContext<Self, State>, response: Response) {
+        self.remember_session(&response);
+        if let Err(e) = self.send(context, &response) {
+            warn!("Unable to send response: {}", e);
+        }
+    }
+
     /// Serialize the data and send it to the websocket
     fn send(&self, context: &mut WebsocketContext<Self, State>, response: &Response) -> Result<(), Error> {
         context.binary(to_vec_packed(&response)?);
         Ok(())
     }
 
-    fn handle_request_login_credentials(
+    /// Track the session bound to this connection, so it can be cleaned up if the connection dies
+    fn remember_session(&mut self, response: &Response) {
+        if let Response::Login(Ok(ref session)) = response {
+            self.current_session = Some(session.clone());
+        }
+    }
+
+    /// Step 1 of OPAQUE registration: answer the client's blinded request using the server's setup
+    fn handle_request_opaque_register_start(
+        &mut self,
+        username: String,
+        registration_request: Vec<u8>,
+        context: &mut WebsocketContext<Self, State>,
+    ) -> Fut<Vec<u8>> {
+        debug!("User {} is registering a password via OPAQUE", username);
+
+        let result = opaque::register_start(&context.state().opaque_setup, &username, &registration_request)
+            .map_err(ResponseError::from);
+
+        if result.is_ok() {
+            self.pending_opaque_registration = Some(PendingOpaqueRegistration { username });
+        }
+
+        Box::new(actix::fut::result(result))
+    }
+
+    /// Step 2 of OPAQUE registration: persist the resulting password file
+    fn handle_request_opaque_register_finish(
+        &mut self,
+        username: String,
+        registration_upload: Vec<u8>,
+        context: &mut WebsocketContext<Self, State>,
+    ) -> Fut<()> {
+        let pending = match self.pending_opaque_registration.take() {
+            Some(pending) if pending.username == username => pending,
+            _ => return Box::new(actix::fut::err(ResponseError::NoPendingChallenge)),
+        };
+        drop(pending);
+
+        let password_file = match opaque::register_finish(&registration_upload) {
+            Ok(password_file) => password_file,
+            Err(e) => return Box::new(actix::fut::err(ResponseError::from(e))),
+        };
+
+        let fut = db_call(context.state().database.send(StorePasswordFile { username, password_file })).into_actor(self);
+
+        Box::new(fut)
+    }
+
+    /// Step 1 of OPAQUE login: run login-start against the stored password file
+    fn handle_request_opaque_login_start(
+        &mut self,
+        username: String,
+        credential_request: Vec<u8>,
+        context: &mut WebsocketContext<Self, State>,
+    ) -> Fut<Vec<u8>> {
+        debug!("User {} is trying to login via OPAQUE", username);
+
+        let opaque_setup = context.state().opaque_setup.clone();
+        let fut = db_call(context.state().database.send(FetchPasswordFile(username.clone())))
+            .into_actor(self)
+            .and_then(move |password_file, actor, _| {
+                // `password_file` is `None` for an unregistered username; `opaque::login_start`
+                // still runs and returns a dummy-but-indistinguishable challenge in that case, so
+                // an observer can't tell a real username from a made-up one by the response shape
+                match opaque::login_start(&opaque_setup, &username, password_file.as_deref(), &credential_request) {
+                    Ok((response, server_login_state)) => {
+                        actor.pending_opaque_login = Some(PendingOpaqueLogin {
+                            username: username.clone(),
+                            server_login_state,
+                        });
+                        Box::new(actix::fut::ok(response))
+                    }
+                    Err(e) => Box::new(actix::fut::err(ResponseError::from(e))),
+                }
+            });
+
+        Box::new(fut)
+    }
+
+    /// Step 2 of OPAQUE login: verify the client's finalization and mint a session
+    fn handle_request_opaque_login_finish(
         &mut self,
-        username: &str,
-        password: &str,
+        username: String,
+        credential_finalization: Vec<u8>,
         context: &mut WebsocketContext<Self, State>,
-    ) -> Result<Session, ResponseError> {
-        debug!("User {} is trying to login", username);
+    ) -> Fut<Session> {
+        let pending = match self.pending_opaque_login.take() {
+            Some(pending) if pending.username == username => pending,
+            _ => return Box::new(actix::fut::err(ResponseError::NoPendingChallenge)),
+        };
 
-        // Error if username and password are invalid
-        if username.is_empty() || password.is_empty() || username != password {
-            debug!("Wrong username or password");
-            return Err(ResponseError::WrongUsernamePassword);
+        // Finishing derives (and implicitly verifies) the shared session key; a mismatched
+        // finalization fails here rather than handing out a session token
+        if let Err(e) = opaque::login_finish(&pending.server_login_state, &credential_finalization) {
+            return Box::new(actix::fut::err(ResponseError::from(e)));
         }
 
-        // Create a new session
-        let session = context
-            .state()
-            .database
-            .send(CreateSession(Token::create(username)?))
-            .wait()
-            .map_err(|_| ResponseError::Database)??;
+        let token_key = &context.state().token_key;
+        let fut = db_call(context.state().database.send(CreateSession(Session {
+            access_token: Token::create_access(token_key, &username),
+            refresh_token: Token::create_refresh(token_key, &username),
+        })))
+        .into_actor(self);
 
-        // Return the session
-        Ok(session)
+        Box::new(fut)
     }
 
     fn handle_request_login_token(
         &mut self,
-        session: &Session,
+        session: Session,
         context: &mut WebsocketContext<Self, State>,
-    ) -> Result<Session, ResponseError> {
-        debug!("Session token {} wants to be renewed", session.token);
+    ) -> Fut<Session> {
+        debug!("Session with refresh token {} wants to be renewed", session.refresh_token);
+
+        let token_key = &context.state().token_key;
+
+        // The access token being unexpired isn't enough on its own: it says nothing about whether
+        // the session has since been explicitly logged out, so even the fast path has to confirm
+        // the stored session is still live rather than skipping the database entirely
+        if Token::seconds_until_expiry(token_key, &session.access_token) > 0 {
+            let fut = db_call(context.state().database.send(IsSessionLive(session.refresh_token.clone())))
+                .into_actor(self)
+                .and_then(move |live, _, _| {
+                    if live {
+                        actix::fut::ok(session)
+                    } else {
+                        actix::fut::err(ResponseError::UnknownCredential)
+                    }
+                });
+
+            return Box::new(fut);
+        }
+
+        let new_access_token = match Token::renew_access(token_key, &session.refresh_token) {
+            Ok(token) => token,
+            Err(e) => return Box::new(actix::fut::err(ResponseError::from(e))),
+        };
+
+        let fut = db_call(context.state().database.send(UpdateSession {
+            refresh_token: session.refresh_token,
+            new_access_token,
+        }))
+        .into_actor(self);
+
+        Box::new(fut)
+    }
 
-        // Try to verify and create a new session
-        let new_session = context
-            .state()
-            .database
-            .send(UpdateSession {
-                old_token: session.token.to_owned(),
-                new_token: Token::verify(&session.token)?,
-            })
-            .wait()
-            .map_err(|_| ResponseError::Database)??;
+    fn handle_request_logout(&mut self, session: Session, context: &mut WebsocketContext<Self, State>) -> Fut<()> {
+        // Invalidate the session; the reaper deletes the row on its next sweep
+        let fut = db_call(context.state().database.send(DeleteSession(session.refresh_token))).into_actor(self);
 
-        // Return the new session
-        Ok(new_session)
+        Box::new(fut)
     }
 
-    fn handle_request_logout(
+    /// Step 1 of registration: hand the authenticator a fresh challenge and remember it
+    fn handle_request_webauthn_register_start(
         &mut self,
-        session: Session,
+        username: String,
         context: &mut WebsocketContext<Self, State>,
-    ) -> Result<(), ResponseError> {
-        // Remove the session from the internal storage
-        context
-            .state()
-            .database
-            .send(DeleteSession(session.token))
-            .wait()
-            .map_err(|_| ResponseError::Database)??;
+    ) -> Fut<PublicKeyCredentialCreationOptions> {
+        debug!("User {} wants to register a WebAuthn credential", username);
 
-        Ok(())
+        let fut = db_call(context.state().database.send(FetchWebAuthnCredentials(username.clone())))
+            .into_actor(self)
+            .map(move |existing, actor, _| {
+                let mut challenge = vec![0u8; 32];
+                thread_rng().fill_bytes(&mut challenge);
+                let mut user_handle = vec![0u8; 16];
+                thread_rng().fill_bytes(&mut user_handle);
+
+                actor.pending_webauthn_registration = Some(PendingRegistration {
+                    username,
+                    challenge: challenge.clone(),
+                });
+
+                PublicKeyCredentialCreationOptions {
+                    challenge,
+                    rp_id: RP_ID.to_owned(),
+                    rp_name: RP_NAME.to_owned(),
+                    user_handle,
+                    excluded_credential_ids: existing.into_iter().map(|c| c.credential_id).collect(),
+                }
+            });
+
+        Box::new(fut)
+    }
+
+    /// Step 2 of registration: verify the attestation and persist the credential
+    fn handle_request_webauthn_register_finish(
+        &mut self,
+        username: String,
+        attestation: AttestationResponse,
+        context: &mut WebsocketContext<Self, State>,
+    ) -> Fut<()> {
+        let pending = match self.pending_webauthn_registration.take() {
+            Some(pending) if pending.username == username => pending,
+            _ => return Box::new(actix::fut::err(ResponseError::NoPendingChallenge)),
+        };
+
+        // Verify the attestation covers the challenge we issued and extract the credential's
+        // public key and initial signature counter from its signed `authData`, rather than
+        // trusting whatever the client reports
+        let verified = match webauthn::verify_attestation(RP_ID, RP_ORIGIN, &pending.challenge, &attestation) {
+            Ok(verified) => verified,
+            Err(e) => return Box::new(actix::fut::err(ResponseError::from(e))),
+        };
+
+        let fut = db_call(context.state().database.send(StoreWebAuthnCredential {
+            username,
+            credential_id: attestation.credential_id,
+            public_key: verified.public_key,
+            signature_count: verified.sign_count,
+        }))
+        .into_actor(self);
+
+        Box::new(fut)
+    }
+
+    /// Step 1 of authentication: list the user's credentials and hand out a challenge
+    fn handle_request_webauthn_login_start(
+        &mut self,
+        username: String,
+        context: &mut WebsocketContext<Self, State>,
+    ) -> Fut<PublicKeyCredentialRequestOptions> {
+        debug!("User {} is trying to login with WebAuthn", username);
+
+        let fut = db_call(context.state().database.send(FetchWebAuthnCredentials(username.clone())))
+            .into_actor(self)
+            .and_then(move |existing, actor, _| {
+                if existing.is_empty() {
+                    return Box::new(actix::fut::err(ResponseError::UnknownCredential))
+                        as Fut<PublicKeyCredentialRequestOptions>;
+                }
+
+                let mut challenge = vec![0u8; 32];
+                thread_rng().fill_bytes(&mut challenge);
+
+                actor.pending_webauthn_authentication = Some(PendingAuthentication {
+                    username,
+                    challenge: challenge.clone(),
+                });
+
+                Box::new(actix::fut::ok(PublicKeyCredentialRequestOptions {
+                    challenge,
+                    rp_id: RP_ID.to_owned(),
+                    allowed_credential_ids: existing.into_iter().map(|c| c.credential_id).collect(),
+                }))
+            });
+
+        Box::new(fut)
+    }
+
+    /// Step 2 of authentication: verify the assertion against the stored public key and mint a session
+    fn handle_request_webauthn_login_finish(
+        &mut self,
+        username: String,
+        assertion: AssertionResponse,
+        context: &mut WebsocketContext<Self, State>,
+    ) -> Fut<Session> {
+        let pending = match self.pending_webauthn_authentication.take() {
+            Some(pending) if pending.username == username => pending,
+            _ => return Box::new(actix::fut::err(ResponseError::NoPendingChallenge)),
+        };
+
+        let fut = db_call(context.state().database.send(FetchWebAuthnCredentials(username.clone())))
+            .into_actor(self)
+            .and_then(move |credentials, actor, context| {
+                let credential = match credentials.into_iter().find(|c| c.credential_id == assertion.credential_id) {
+                    Some(credential) => credential,
+                    None => return Box::new(actix::fut::err(ResponseError::UnknownCredential)) as Fut<Session>,
+                };
+
+                // The counter is only trustworthy once it's been parsed out of the bytes the
+                // signature actually covers; a counter that doesn't advance means the
+                // authenticator was cloned, so reject the assertion (unless both the stored and
+                // the new counter are 0, i.e. the authenticator keeps no counter at all)
+                let signature_count = match webauthn::verify_assertion(RP_ID, RP_ORIGIN, &pending.challenge, &credential.public_key, &assertion) {
+                    Ok(signature_count) => signature_count,
+                    Err(e) => return Box::new(actix::fut::err(ResponseError::from(e))),
+                };
+
+                if (signature_count != 0 || credential.signature_count != 0) && signature_count <= credential.signature_count {
+                    debug!("Rejecting WebAuthn assertion with a stale signature counter");
+                    return Box::new(actix::fut::err(ResponseError::UnknownCredential));
+                }
+
+                let credential_id = credential.credential_id;
+
+                let fut = db_call(context.state().database.send(UpdateSignatureCounter {
+                    credential_id,
+                    signature_count,
+                }))
+                .into_actor(actor)
+                .and_then(move |_, actor, context| {
+                    let token_key = &context.state().token_key;
+                    db_call(context.state().database.send(CreateSession(Session {
+                        access_token: Token::create_access(token_key, &username),
+                        refresh_token: Token::create_refresh(token_key, &username),
+                    })))
+                    .into_actor(actor)
+                });
+
+                Box::new(fut)
+            });
+
+        Box::new(fut)
     }
 }
@@ -0,0 +1,127 @@
+//! Types shared between the backend and the frontend over the websocket wire
+
+use failure::Error;
+use serde_derive::{Deserialize, Serialize};
+
+/// A request sent from the client to the server
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Login(Login),
+    Logout(Session),
+}
+
+/// The ways a client can authenticate
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Login {
+    /// Renew an existing session
+    Session(Session),
+    /// Step 1 of OPAQUE registration: the client's blinded `RegistrationRequest`
+    OpaqueRegisterStart { username: String, registration_request: Vec<u8> },
+    /// Step 2 of OPAQUE registration: the client's `RegistrationUpload`
+    OpaqueRegisterFinish { username: String, registration_upload: Vec<u8> },
+    /// Step 1 of OPAQUE login: the client's blinded `CredentialRequest`
+    OpaqueLoginStart { username: String, credential_request: Vec<u8> },
+    /// Step 2 of OPAQUE login: the client's `CredentialFinalization`
+    OpaqueLoginFinish { username: String, credential_finalization: Vec<u8> },
+    /// Step 1 of WebAuthn registration: ask the server for a challenge
+    WebAuthnRegisterStart { username: String },
+    /// Step 2 of WebAuthn registration: hand back the attestation
+    WebAuthnRegisterFinish {
+        username: String,
+        attestation: AttestationResponse,
+    },
+    /// Step 1 of WebAuthn authentication: ask the server for a challenge
+    WebAuthnLoginStart { username: String },
+    /// Step 2 of WebAuthn authentication: hand back the assertion
+    WebAuthnLoginFinish {
+        username: String,
+        assertion: AssertionResponse,
+    },
+}
+
+/// A response sent from the server to the client
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Login(Result<Session, ResponseError>),
+    Logout(Result<(), ResponseError>),
+    WebAuthnRegisterChallenge(Result<PublicKeyCredentialCreationOptions, ResponseError>),
+    WebAuthnRegistered(Result<(), ResponseError>),
+    WebAuthnLoginChallenge(Result<PublicKeyCredentialRequestOptions, ResponseError>),
+    /// The server's `RegistrationResponse`
+    OpaqueRegisterChallenge(Result<Vec<u8>, ResponseError>),
+    OpaqueRegistered(Result<(), ResponseError>),
+    /// The server's `CredentialResponse`
+    OpaqueLoginChallenge(Result<Vec<u8>, ResponseError>),
+}
+
+/// An authenticated session
+///
+/// The access token is short-lived; the refresh token identifies the session on the server, mints
+/// a new access token once the old one expires, and is what a logout invalidates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+/// The challenge the server hands the authenticator for registration (`navigator.credentials.create`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicKeyCredentialCreationOptions {
+    pub challenge: Vec<u8>,
+    pub rp_id: String,
+    pub rp_name: String,
+    pub user_handle: Vec<u8>,
+    pub excluded_credential_ids: Vec<Vec<u8>>,
+}
+
+/// The challenge the server hands the authenticator for authentication (`navigator.credentials.get`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicKeyCredentialRequestOptions {
+    pub challenge: Vec<u8>,
+    pub rp_id: String,
+    pub allowed_credential_ids: Vec<Vec<u8>>,
+}
+
+/// What the authenticator returns after a registration ceremony
+///
+/// The public key isn't a field here: it's untrusted client input until it's been extracted from
+/// `attestation_object`'s signed `authData` and verified, so it's derived server-side instead of
+/// taken at face value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttestationResponse {
+    pub credential_id: Vec<u8>,
+    pub attestation_object: Vec<u8>,
+    pub client_data_json: Vec<u8>,
+}
+
+/// What the authenticator returns after an authentication ceremony
+///
+/// There's no client-reported signature counter here: the counter used for clone detection is
+/// parsed out of the verified `authenticator_data` instead, since a client-supplied one isn't
+/// covered by anything the signature authenticates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssertionResponse {
+    pub credential_id: Vec<u8>,
+    pub authenticator_data: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub client_data_json: Vec<u8>,
+}
+
+/// Errors that can be returned to the client
+#[derive(Debug, Fail, Serialize, Deserialize)]
+pub enum ResponseError {
+    #[fail(display = "Wrong username or password")]
+    WrongUsernamePassword,
+    #[fail(display = "Unknown or unregistered credential")]
+    UnknownCredential,
+    #[fail(display = "No pending ceremony for this connection")]
+    NoPendingChallenge,
+    #[fail(display = "Database error")]
+    Database,
+}
+
+impl From<Error> for ResponseError {
+    fn from(_: Error) -> Self {
+        ResponseError::Database
+    }
+}